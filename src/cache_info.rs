@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use eyre::{bail, eyre, Result};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::redact::redact_url;
+use crate::STORE_DIR;
+
+/// The parsed contents of a binary cache's `nix-cache-info` file.
+pub struct CacheInfo {
+    pub store_dir: String,
+    pub want_mass_query: Option<bool>,
+    pub priority: Option<u32>,
+}
+
+impl CacheInfo {
+    /// Parses a `nix-cache-info` document.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut store_dir = Err(eyre!("failed to find StoreDir"));
+        let mut want_mass_query = None;
+        let mut priority = None;
+        for line in contents.lines() {
+            let (key, val) = line
+                .split_once(": ")
+                .ok_or_else(|| eyre!("invalid nix-cache-info line: {}", line))?;
+            match key {
+                "StoreDir" => store_dir = Ok(String::from(val)),
+                "WantMassQuery" => {
+                    want_mass_query = Some(
+                        val.parse::<u8>()
+                            .map_err(|e| eyre!("invalid WantMassQuery: {}", e))?
+                            != 0,
+                    )
+                }
+                "Priority" => {
+                    priority = Some(
+                        val.parse::<u32>()
+                            .map_err(|e| eyre!("invalid Priority: {}", e))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        Ok(CacheInfo {
+            store_dir: store_dir?,
+            want_mass_query,
+            priority,
+        })
+    }
+}
+
+/// Fetches `<cache_url>/nix-cache-info`, validates that its `StoreDir` matches the
+/// `/nix/store` prefix `store_path_to_narinfo_hash` assumes, and writes a copy into
+/// `mirror_dir` so the resulting mirror is itself a servable binary cache.
+pub async fn fetch_cache_info(
+    client: &reqwest::Client,
+    cache_url: &str,
+    mirror_dir: &Path,
+) -> Result<CacheInfo> {
+    let url = format!("{}/nix-cache-info", cache_url);
+    let safe_url = redact_url(&url);
+    let body = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| eyre!("failed to GET {}: {}", safe_url, e.without_url()))?
+        .error_for_status()
+        .map_err(|e| eyre!("GET {} returned an error status: {}", safe_url, e.without_url()))?
+        .text()
+        .await
+        .map_err(|e| eyre!("failed reading response body from {}: {}", safe_url, e.without_url()))?;
+
+    let cache_info = CacheInfo::parse(&body)?;
+    if cache_info.store_dir != STORE_DIR {
+        bail!(
+            "cache's StoreDir {} does not match the assumed store directory {}",
+            cache_info.store_dir,
+            STORE_DIR
+        );
+    }
+
+    let mut f = fs::File::create(mirror_dir.join("nix-cache-info")).await?;
+    f.write_all(body.as_bytes()).await?;
+
+    Ok(cache_info)
+}
@@ -0,0 +1,85 @@
+use eyre::{bail, eyre, Result};
+use md5::Md5;
+use nix_base32::to_nix_base32;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+/// A narinfo `FileHash` value: the digest algorithm together with the expected
+/// nix-base32-encoded digest.
+pub enum FileHash {
+    Sha256(String),
+    Sha512(String),
+    Sha1(String),
+    Md5(String),
+}
+
+impl FileHash {
+    /// Parses a narinfo `FileHash` value, e.g. `sha256:1gl4v...`.
+    /// ```
+    /// use nix_mirror::hash::FileHash;
+    /// assert!(matches!(FileHash::parse("sha256:abc").unwrap(), FileHash::Sha256(_)));
+    /// ```
+    pub fn parse(s: &str) -> Result<Self> {
+        let (algo, digest) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("invalid FileHash, expected algo:digest: {}", s))?;
+        let digest = String::from(digest);
+        match algo {
+            "sha256" => Ok(FileHash::Sha256(digest)),
+            "sha512" => Ok(FileHash::Sha512(digest)),
+            "sha1" => Ok(FileHash::Sha1(digest)),
+            "md5" => Ok(FileHash::Md5(digest)),
+            other => bail!("unsupported FileHash algorithm: {}", other),
+        }
+    }
+
+    /// The nix-base32-encoded digest the hash is expected to produce.
+    pub fn expected(&self) -> &str {
+        match self {
+            FileHash::Sha256(digest)
+            | FileHash::Sha512(digest)
+            | FileHash::Sha1(digest)
+            | FileHash::Md5(digest) => digest,
+        }
+    }
+}
+
+/// A running digest of one of the algorithms a `FileHash` can name.
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Sha1(Sha1),
+    Md5(Md5),
+}
+
+impl Hasher {
+    /// Creates the hasher matching the algorithm `hash` was parsed as.
+    pub fn new(hash: &FileHash) -> Self {
+        match hash {
+            FileHash::Sha256(_) => Hasher::Sha256(Sha256::new()),
+            FileHash::Sha512(_) => Hasher::Sha512(Sha512::new()),
+            FileHash::Sha1(_) => Hasher::Sha1(Sha1::new()),
+            FileHash::Md5(_) => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finalizes the digest and encodes it in nix-base32, the representation
+    /// `FileHash::expected` values are given in.
+    pub fn finalize_nix_base32(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => to_nix_base32(&hasher.finalize()),
+            Hasher::Sha512(hasher) => to_nix_base32(&hasher.finalize()),
+            Hasher::Sha1(hasher) => to_nix_base32(&hasher.finalize()),
+            Hasher::Md5(hasher) => to_nix_base32(&hasher.finalize()),
+        }
+    }
+}
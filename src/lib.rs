@@ -1,3 +1,9 @@
+pub mod cache_info;
+pub mod hash;
+pub mod nar;
+pub mod redact;
+pub mod signature;
+
 use std::path::Path;
 
 use futures::stream::StreamExt;
@@ -8,7 +14,16 @@ use tokio::task;
 use eyre::{bail, eyre, Result};
 use nix_base32::to_nix_base32;
 use path_clean::PathClean;
-use sha2::{Digest, Sha256};
+use sha2::Digest;
+
+use hash::{FileHash, Hasher};
+use nar::NarVerify;
+use redact::redact_url;
+use signature::{verify_narinfo_signature, TrustedKeys};
+
+/// The nix store directory assumed throughout, e.g. when reconstructing full store
+/// paths from the basenames found in a narinfo's `References` field.
+pub(crate) const STORE_DIR: &str = "/nix/store";
 
 /// Extracts the narinfo hash part from a nix store filename.
 /// ```
@@ -43,20 +58,29 @@ pub fn store_path_to_narinfo_hash(store_path: &str) -> Result<&str> {
 /// `client` - a reqwest::Client, as given by `reqwest::Client::new()`.
 /// `url` - the url we want to download
 /// `destination` - where we want the resulting file to end up
-/// `hash` - optionally a sha256-digest (in nix-base32) of the file that will be checked
+/// `hash` - optionally the algorithm and expected digest (in nix-base32) of the file
+/// `nar_verify` - optionally the decompressed NarHash/NarSize the file is expected to
+/// produce once decompressed, which is checked concurrently with the download
 pub async fn download_atomically(
     client: &reqwest::Client,
     url: String,
     destination: &Path,
-    hash: Option<&str>,
+    hash: Option<&FileHash>,
+    nar_verify: Option<&NarVerify>,
 ) -> Result<fs::File> {
+    // keep a credential-free copy of the url around for error messages, so a
+    // `--cache-url` with embedded `user:password@` never ends up in the logs
+    let safe_url = redact_url(&url);
+
     let mut resp_stream = client
         .get(&url)
         .send()
-        .await?
-        .error_for_status()?
+        .await
+        .map_err(|e| eyre!("failed to GET {}: {}", safe_url, e.without_url()))?
+        .error_for_status()
+        .map_err(|e| eyre!("GET {} returned an error status: {}", safe_url, e.without_url()))?
         .bytes_stream();
-    let mut ctx = hash.map(|_| Sha256::new());
+    let mut ctx = hash.map(Hasher::new);
 
     let destination_dir = destination.parent().unwrap();
     let result: Result<_> = task::block_in_place(|| {
@@ -66,26 +90,72 @@ pub async fn download_atomically(
     });
     let (tempfile, mut async_file) = result?;
 
+    // if we also need to verify the decompressed contents, pipe a copy of every
+    // downloaded chunk through the appropriate decompressor on a background task as
+    // we go, rather than buffering the whole (potentially huge) NAR in memory first
+    let mut nar_pipe = match nar_verify {
+        Some(verify) => {
+            let (writer, reader) = io::duplex(64 * 1024);
+            let compression = verify.compression;
+            let handle =
+                task::spawn(
+                    async move { nar::hash_decompressed(io::BufReader::new(reader), compression).await },
+                );
+            Some((writer, handle))
+        }
+        None => None,
+    };
+
     while let Some(bytes) = resp_stream.next().await {
-        let bytes = bytes?;
+        let bytes =
+            bytes.map_err(|e| eyre!("failed reading response body from {}: {}", safe_url, e.without_url()))?;
         if let Some(ctx) = ctx.as_mut() {
             ctx.update(&bytes);
         }
+        if let Some((writer, _)) = nar_pipe.as_mut() {
+            writer.write_all(&bytes).await?;
+        }
         async_file.write_all(&bytes).await?;
     }
     async_file.shutdown().await?;
     if let Some(ctx) = ctx {
         let hash = hash.unwrap();
-        let computed = to_nix_base32(&ctx.finalize().as_ref());
-        if computed != hash {
+        let computed = ctx.finalize_nix_base32();
+        if computed != hash.expected() {
             bail!(
-                "hash of file: {:?} failed, expected: {}, got: {}",
+                "hash of {} (downloaded to {:?}) failed, expected: {}, got: {}",
+                safe_url,
                 destination,
-                hash,
+                hash.expected(),
                 computed
             );
         }
     }
+    if let Some((mut writer, handle)) = nar_pipe {
+        writer.shutdown().await?;
+        drop(writer);
+        let (hasher, size) = handle.await??;
+        let verify = nar_verify.unwrap();
+        let computed = format!("sha256:{}", to_nix_base32(&hasher.finalize()));
+        if computed != verify.nar_hash {
+            bail!(
+                "NarHash of {} (downloaded to {:?}) failed, expected: {}, got: {}",
+                safe_url,
+                destination,
+                verify.nar_hash,
+                computed
+            );
+        }
+        if size != verify.nar_size {
+            bail!(
+                "NarSize of {} (downloaded to {:?}) failed, expected: {}, got: {}",
+                safe_url,
+                destination,
+                verify.nar_size,
+                size
+            );
+        }
+    }
 
     let f = task::block_in_place(|| tempfile.persist(&destination))?;
     let mut f = fs::File::from(f);
@@ -104,6 +174,9 @@ pub async fn handle_narinfo(
     cache_url: &String,
     mirror_dir: &Path,
     narinfo_hash: String,
+    trusted_keys: &TrustedKeys,
+    verify_nar_contents: bool,
+    unpack_dir: Option<&Path>,
 ) -> Result<Vec<String>> {
     let mut narinfo_filename = mirror_dir.join(&narinfo_hash);
     narinfo_filename.set_extension("narinfo");
@@ -115,7 +188,7 @@ pub async fn handle_narinfo(
         f
     } else {
         let url = format!("{}/{}.narinfo", cache_url, &narinfo_hash);
-        download_atomically(client, url, &narinfo_filename, None).await?
+        download_atomically(client, url, &narinfo_filename, None, None).await?
     };
 
     let narinfo_file = io::BufReader::new(narinfo_file);
@@ -124,26 +197,41 @@ pub async fn handle_narinfo(
     // ugly parser, but it would be overkill to reach for a parsing library here
     let mut url = Err(eyre!("failed to find URL"));
     let mut references = Vec::new();
-    let mut filehash = Err(eyre!("failed to find filehash"));
+    let mut reference_basenames = Vec::new();
+    let mut filehash: Result<FileHash> = Err(eyre!("failed to find filehash"));
+    let mut store_path = Err(eyre!("failed to find StorePath"));
+    let mut nar_hash = Err(eyre!("failed to find NarHash"));
+    let mut nar_size = Err(eyre!("failed to find NarSize"));
+    let mut compression = None;
+    let mut sigs = Vec::new();
     while let Some(line) = lines.next_line().await? {
         let mut split = line.splitn(2, ": ");
         let key = split.next().ok_or_else(|| eyre!("failed to find key"))?;
         let val = split.next().ok_or_else(|| eyre!("failed to find val"))?;
         match key {
             "URL" => url = Ok(String::from(val)),
+            "StorePath" => store_path = Ok(String::from(val)),
+            "NarHash" => nar_hash = Ok(String::from(val)),
+            "NarSize" => {
+                nar_size = val
+                    .parse::<u64>()
+                    .map_err(|e| eyre!("invalid NarSize: {}", e))
+            }
             "References" => {
-                references = val
-                    .split_whitespace()
-                    .flat_map(|x| x.split("-").next())
+                reference_basenames = val.split_whitespace().map(String::from).collect::<Vec<_>>();
+                references = reference_basenames
+                    .iter()
+                    .flat_map(|x| x.split('-').next())
                     .map(String::from)
                     .collect()
             }
-            "FileHash" => {
-                filehash = val
-                    .split(':')
-                    .nth(1)
-                    .map(String::from)
-                    .ok_or_else(|| eyre!("invalid filehash"))
+            "FileHash" => filehash = FileHash::parse(val),
+            "Compression" => compression = Some(nar::Compression::parse(val)?),
+            "Sig" => {
+                let (name, sig) = val
+                    .split_once(':')
+                    .ok_or_else(|| eyre!("invalid Sig line: {}", val))?;
+                sigs.push((String::from(name), String::from(sig)));
             }
             _ => {}
         }
@@ -152,12 +240,57 @@ pub async fn handle_narinfo(
     // we error out if we didn't find an url or a filehash
     let url = url?;
     let filehash = filehash?;
+    let store_path = store_path?;
+    let nar_hash = nar_hash?;
+    let nar_size = nar_size?;
+
+    // reconstruct the fingerprint nix itself signs, so we can check it against the
+    // keys in `trusted_keys`. this needs the full reference basenames
+    // (hash-name-version), not just the hash prefixes used for recursion below.
+    let reference_store_paths = reference_basenames
+        .iter()
+        .map(|reference| format!("{}/{}", STORE_DIR, reference))
+        .collect::<Vec<_>>()
+        .join(",");
+    let fingerprint = format!(
+        "1;{};{};{};{}",
+        store_path, nar_hash, nar_size, reference_store_paths
+    );
+    verify_narinfo_signature(&fingerprint, &sigs, trusted_keys)?;
+
+    // if requested, also verify the decompressed contents against NarHash/NarSize,
+    // which requires knowing how the nar was compressed
+    let nar_verify = if verify_nar_contents {
+        let compression = compression
+            .ok_or_else(|| eyre!("missing Compression field, required to verify NAR contents"))?;
+        Some(NarVerify {
+            compression,
+            nar_hash,
+            nar_size,
+        })
+    } else {
+        None
+    };
 
     // check to see if we need to download the nar archive, if so do it
     let filename = mirror_dir.join(&url).clean();
     if fs::File::open(&filename).await.is_err() {
         let url = format!("{}/{}", cache_url, &url);
-        download_atomically(client, url, &filename, Some(&filehash)).await?;
+        download_atomically(client, url, &filename, Some(&filehash), nar_verify.as_ref()).await?;
+    }
+
+    // if requested, unpack the nar into a real store-path tree alongside the mirror
+    if let Some(unpack_dir) = unpack_dir {
+        let compression = compression
+            .ok_or_else(|| eyre!("missing Compression field, required to unpack NAR"))?;
+        let basename = store_path
+            .rsplit('/')
+            .next()
+            .ok_or_else(|| eyre!("invalid StorePath: {}", store_path))?;
+        let destination = unpack_dir.join(basename);
+        if fs::metadata(&destination).await.is_err() {
+            nar::unpack(&filename, compression, &destination).await?;
+        }
     }
 
     Ok(references.into_iter().map(String::from).collect())
@@ -1,3 +1,5 @@
+use nix_mirror::cache_info::fetch_cache_info;
+use nix_mirror::signature::parse_trusted_keys;
 use nix_mirror::{handle_narinfo, store_path_to_narinfo_hash};
 
 use std::collections::HashSet;
@@ -27,6 +29,22 @@ struct Opt {
     /// Maximum number of concurrent downloads
     #[structopt(short, long, default_value = "8")]
     parallelism: usize,
+
+    /// A public key trusted to sign narinfos, in the form `name:base64key`, e.g.
+    /// `cache.nixos.org-1:6NCHdD59X431o0gWypXEtJhNbB7eKXaZZo8sFVwaiow=`. May be given
+    /// multiple times. If none are given, narinfo signatures are not checked.
+    #[structopt(long)]
+    trusted_public_key: Vec<String>,
+
+    /// Decompress each downloaded NAR and verify it against the narinfo's NarHash and
+    /// NarSize, in addition to the FileHash check on the compressed download
+    #[structopt(long)]
+    verify_nar_contents: bool,
+
+    /// If given, unpack each downloaded NAR into a real filesystem tree under this
+    /// directory, named after its StorePath basename
+    #[structopt(long)]
+    unpack_dir: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -34,6 +52,9 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
     let nar_dir = opt.mirror_dir.join("nar");
     fs::create_dir_all(&nar_dir).await?;
+    if let Some(unpack_dir) = &opt.unpack_dir {
+        fs::create_dir_all(unpack_dir).await?;
+    }
 
     // read all store paths to memory, there aren't that many of
     // them, so we might as well read all of them into memory
@@ -52,6 +73,13 @@ async fn main() -> Result<()> {
 
     let client = reqwest::Client::new();
 
+    // fetch nix-cache-info before processing any store paths, both to make sure the
+    // cache's StoreDir matches what we assume and to leave a copy in mirror_dir so
+    // the mirror is itself a servable binary cache
+    fetch_cache_info(&client, &opt.cache_url, &opt.mirror_dir).await?;
+
+    let trusted_keys = parse_trusted_keys(&opt.trusted_public_key)?;
+
     // our initial set of narinfo hashes to process
     let mut current_narinfo_hashes = store_paths
         .lines()
@@ -68,6 +96,9 @@ async fn main() -> Result<()> {
                 &opt.cache_url,
                 &opt.mirror_dir,
                 narinfo_hash.clone(),
+                &trusted_keys,
+                opt.verify_nar_contents,
+                opt.unpack_dir.as_deref(),
             ));
             processed_narinfo_hashes.insert(narinfo_hash);
             progress.inc_length(1);
@@ -0,0 +1,333 @@
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use eyre::{bail, eyre, Result};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{self, AsyncBufRead, AsyncRead, AsyncReadExt};
+
+/// The compression codec a narinfo's `Compression` field declares for its NAR.
+#[derive(Clone, Copy)]
+pub enum Compression {
+    Xz,
+    Bzip2,
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl Compression {
+    /// Parses a narinfo `Compression` value.
+    /// ```
+    /// use nix_mirror::nar::Compression;
+    /// assert!(matches!(Compression::parse("xz").unwrap(), Compression::Xz));
+    /// ```
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "xz" => Ok(Compression::Xz),
+            "bzip2" | "bz2" => Ok(Compression::Bzip2),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            other => bail!("unsupported Compression: {}", other),
+        }
+    }
+}
+
+/// The decompressed `NarHash`/`NarSize` a downloaded NAR is expected to produce, so
+/// that `download_atomically` can verify the uncompressed contents in addition to the
+/// compressed `FileHash`.
+pub struct NarVerify {
+    pub compression: Compression,
+    pub nar_hash: String,
+    pub nar_size: u64,
+}
+
+/// Wraps `reader` in the decompressor matching `compression`.
+fn decoder<R>(reader: R, compression: Compression) -> Box<dyn AsyncRead + Unpin + Send>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    match compression {
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+        Compression::Gzip => Box::new(GzipDecoder::new(reader)),
+        Compression::Zstd => Box::new(ZstdDecoder::new(reader)),
+        Compression::None => Box::new(reader),
+    }
+}
+
+/// Reads `reader` as a NAR compressed with `compression` to completion, returning the
+/// sha256 digest and byte count of its decompressed contents.
+pub async fn hash_decompressed<R>(reader: R, compression: Compression) -> Result<(Sha256, u64)>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    let mut decoder = decoder(reader, compression);
+
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = decoder.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((hasher, size))
+}
+
+/// Upper bound on a single NAR string's declared length. Real NARs never need a
+/// single string anywhere near this big; a `len` beyond it is a corrupt or hostile
+/// stream, and rejecting it up front avoids `vec![0u8; len]` attempting to allocate
+/// an attacker-controlled amount of memory before we ever read a byte of `data`.
+const MAX_TOKEN_LEN: u64 = 1 << 34;
+
+/// Reads a nix-archive-1 (NAR) "string": an 8-byte little-endian length followed by
+/// that many bytes, zero-padded up to the next multiple of 8.
+async fn read_token<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len_buf = [0u8; 8];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u64::from_le_bytes(len_buf);
+    if len > MAX_TOKEN_LEN {
+        bail!(
+            "malformed NAR: string length {} exceeds maximum of {}",
+            len,
+            MAX_TOKEN_LEN
+        );
+    }
+    let len = len as usize;
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+
+    let padding = (8 - len % 8) % 8;
+    if padding > 0 {
+        let mut pad = [0u8; 8];
+        reader.read_exact(&mut pad[..padding]).await?;
+    }
+    Ok(data)
+}
+
+/// Reads a token and checks that it matches `expected`.
+async fn expect_token<R: AsyncRead + Unpin>(reader: &mut R, expected: &str) -> Result<()> {
+    let token = read_token(reader).await?;
+    if token != expected.as_bytes() {
+        bail!(
+            "malformed NAR: expected {:?}, got {:?}",
+            expected,
+            String::from_utf8_lossy(&token)
+        );
+    }
+    Ok(())
+}
+
+/// Unpacks the `regular` fields (`[executable] contents <string>`) of a NAR node,
+/// writing the file to `destination`, and consumes the node's closing `)`.
+async fn unpack_regular<R: AsyncRead + Unpin>(reader: &mut R, destination: &Path) -> Result<()> {
+    let mut executable = false;
+    let mut tag = read_token(reader).await?;
+    if tag == b"executable" {
+        executable = true;
+        expect_token(reader, "").await?;
+        tag = read_token(reader).await?;
+    }
+    if tag != b"contents" {
+        bail!(
+            "malformed NAR: expected \"contents\", got {:?}",
+            String::from_utf8_lossy(&tag)
+        );
+    }
+    let contents = read_token(reader).await?;
+    fs::write(destination, &contents).await?;
+
+    #[cfg(unix)]
+    if executable {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(destination).await?.permissions();
+        perms.set_mode(perms.mode() | 0o111);
+        fs::set_permissions(destination, perms).await?;
+    }
+
+    expect_token(reader, ")").await
+}
+
+/// Unpacks the `symlink` fields (`target <string>`) of a NAR node, creating the
+/// symlink at `destination`, and consumes the node's closing `)`.
+async fn unpack_symlink<R: AsyncRead + Unpin>(reader: &mut R, destination: &Path) -> Result<()> {
+    expect_token(reader, "target").await?;
+    let target = read_token(reader).await?;
+    let target = String::from_utf8(target).map_err(|e| eyre!("invalid symlink target: {}", e))?;
+    fs::symlink(target, destination).await?;
+    expect_token(reader, ")").await
+}
+
+/// Unpacks the `directory` fields (zero or more `entry ( name <string> node <node> )`)
+/// of a NAR node, creating `destination` and its children, and consumes the node's
+/// closing `)`.
+async fn unpack_directory<R: AsyncRead + Unpin + Send>(
+    reader: &mut R,
+    destination: &Path,
+) -> Result<()> {
+    fs::create_dir_all(destination).await?;
+    loop {
+        let tag = read_token(reader).await?;
+        match tag.as_slice() {
+            b"entry" => {
+                expect_token(reader, "(").await?;
+                expect_token(reader, "name").await?;
+                let name = read_token(reader).await?;
+                let name =
+                    String::from_utf8(name).map_err(|e| eyre!("invalid entry name: {}", e))?;
+                if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                    bail!("malformed NAR: unsafe entry name {:?}", name);
+                }
+                expect_token(reader, "node").await?;
+                let child_destination = destination.join(&name);
+                unpack_node(reader, &child_destination).await?;
+                expect_token(reader, ")").await?;
+            }
+            b")" => break,
+            other => bail!(
+                "malformed NAR: expected \"entry\" or \")\", got {:?}",
+                String::from_utf8_lossy(other)
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks a single NAR node (`( type <regular|symlink|directory> ... )`) to
+/// `destination`.
+///
+/// Boxed because `directory` nodes recurse back into this function through
+/// `unpack_directory`, and `async fn`s can't recurse (even indirectly) without it.
+fn unpack_node<'a, R: AsyncRead + Unpin + Send>(
+    reader: &'a mut R,
+    destination: &'a Path,
+) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+    Box::pin(async move {
+        expect_token(reader, "(").await?;
+        expect_token(reader, "type").await?;
+        let node_type = read_token(reader).await?;
+        match node_type.as_slice() {
+            b"regular" => unpack_regular(reader, destination).await,
+            b"symlink" => unpack_symlink(reader, destination).await,
+            b"directory" => unpack_directory(reader, destination).await,
+            other => bail!(
+                "malformed NAR: unknown node type {:?}",
+                String::from_utf8_lossy(other)
+            ),
+        }
+    })
+}
+
+/// Unpacks the NAR at `nar_path` (compressed with `compression`) into a real
+/// filesystem tree rooted at `destination`.
+pub async fn unpack(nar_path: &Path, compression: Compression, destination: &Path) -> Result<()> {
+    let file = fs::File::open(nar_path).await?;
+    let mut reader = decoder(io::BufReader::new(file), compression);
+    expect_token(&mut reader, "nix-archive-1").await?;
+    unpack_node(&mut reader, destination).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a single NAR "string" token the same way the real format does, so
+    /// tests can hand-assemble the byte streams `read_token`/`unpack_node` expect.
+    fn token(s: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s);
+        let padding = (8 - s.len() % 8) % 8;
+        out.extend(std::iter::repeat(0u8).take(padding));
+        out
+    }
+
+    /// Encodes `( type regular contents <data> )`.
+    fn regular_node(data: &[u8]) -> Vec<u8> {
+        [
+            token(b"("),
+            token(b"type"),
+            token(b"regular"),
+            token(b"contents"),
+            token(data),
+            token(b")"),
+        ]
+        .concat()
+    }
+
+    /// Encodes `( type directory entry ( name <name> node <node> ) )` for a single entry.
+    fn directory_node(name: &[u8], node: &[u8]) -> Vec<u8> {
+        [
+            token(b"("),
+            token(b"type"),
+            token(b"directory"),
+            token(b"entry"),
+            token(b"("),
+            token(b"name"),
+            token(name),
+            token(b"node"),
+            node.to_vec(),
+            token(b")"),
+            token(b")"),
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn read_token_rejects_absurd_length_instead_of_aborting() {
+        // a bogus 8-byte length prefix claiming an implausible number of bytes
+        // follow, as a truncated download or a hostile cache might produce
+        let bogus_len = (u64::MAX / 2).to_le_bytes();
+        let mut reader = io::BufReader::new(&bogus_len[..]);
+        let result = read_token(&mut reader).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn unpack_node_rejects_entry_names_that_escape_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("root");
+
+        let bytes = directory_node(b"../escaped", &regular_node(b"hi"));
+        let mut reader = io::BufReader::new(&bytes[..]);
+        let result = unpack_node(&mut reader, &destination).await;
+
+        assert!(result.is_err());
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn unpack_node_rejects_entry_names_containing_a_path_separator() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("root");
+
+        let bytes = directory_node(b"subdir/escaped", &regular_node(b"hi"));
+        let mut reader = io::BufReader::new(&bytes[..]);
+        let result = unpack_node(&mut reader, &destination).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn unpack_node_accepts_a_normal_entry_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("root");
+
+        let bytes = directory_node(b"file.txt", &regular_node(b"hi"));
+        let mut reader = io::BufReader::new(&bytes[..]);
+        unpack_node(&mut reader, &destination).await.unwrap();
+
+        assert_eq!(
+            fs::read_to_string(destination.join("file.txt")).await.unwrap(),
+            "hi"
+        );
+    }
+}
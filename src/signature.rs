@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use eyre::{bail, eyre, Result};
+
+/// Public keys trusted to sign narinfo files, keyed by key name (e.g. `cache.nixos.org-1`).
+pub type TrustedKeys = HashMap<String, VerifyingKey>;
+
+/// Parses a single `--trusted-public-key` argument of the form `name:base64key`.
+/// ```
+/// use nix_mirror::signature::parse_trusted_key;
+/// assert!(parse_trusted_key("missing-a-colon").is_err());
+/// ```
+pub fn parse_trusted_key(s: &str) -> Result<(String, VerifyingKey)> {
+    let (name, key) = s
+        .split_once(':')
+        .ok_or_else(|| eyre!("invalid trusted public key, expected name:base64key: {}", s))?;
+    let key_bytes = base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| eyre!("invalid base64 in trusted public key {}: {}", name, e))?;
+    let key_bytes: [u8; 32] = key_bytes
+        .try_into()
+        .map_err(|_| eyre!("trusted public key {} is not 32 bytes", name))?;
+    let key = VerifyingKey::from_bytes(&key_bytes)?;
+    Ok((String::from(name), key))
+}
+
+/// Parses every `--trusted-public-key` argument into a lookup table keyed by key name.
+pub fn parse_trusted_keys(keys: &[String]) -> Result<TrustedKeys> {
+    keys.iter().map(|s| parse_trusted_key(s)).collect()
+}
+
+/// Verifies that at least one of `sigs` (`(keyname, base64sig)` pairs, as found in a
+/// narinfo's `Sig` lines) is a valid signature over `fingerprint` by the correspondingly
+/// named key in `trusted_keys`.
+///
+/// If `trusted_keys` is empty (i.e. no `--trusted-public-key` was given) verification is
+/// skipped entirely, so narinfos are accepted unconditionally as before.
+pub fn verify_narinfo_signature(
+    fingerprint: &str,
+    sigs: &[(String, String)],
+    trusted_keys: &TrustedKeys,
+) -> Result<()> {
+    if trusted_keys.is_empty() {
+        return Ok(());
+    }
+    for (name, sig) in sigs {
+        let key = match trusted_keys.get(name) {
+            Some(key) => key,
+            None => continue,
+        };
+        let sig_bytes = match base64::engine::general_purpose::STANDARD.decode(sig) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let sig_bytes: [u8; 64] = match sig_bytes.try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let signature = Signature::from_bytes(&sig_bytes);
+        if key.verify(fingerprint.as_bytes(), &signature).is_ok() {
+            return Ok(());
+        }
+    }
+    bail!(
+        "no valid signature found for fingerprint, tried keys: {}",
+        sigs.iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    /// A deterministic keypair so tests don't depend on external key material.
+    fn test_keypair() -> (SigningKey, VerifyingKey) {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let verifying_key = signing_key.verifying_key();
+        (signing_key, verifying_key)
+    }
+
+    fn sign(signing_key: &SigningKey, fingerprint: &str) -> Vec<(String, String)> {
+        let sig = signing_key.sign(fingerprint.as_bytes());
+        vec![(
+            String::from("test-1"),
+            base64::engine::general_purpose::STANDARD.encode(sig.to_bytes()),
+        )]
+    }
+
+    #[test]
+    fn accepts_a_valid_signature_from_a_trusted_key() {
+        let (signing_key, verifying_key) = test_keypair();
+        let fingerprint = "1;/nix/store/abc-foo-1.0;sha256:deadbeef;123;";
+        let sigs = sign(&signing_key, fingerprint);
+
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(String::from("test-1"), verifying_key);
+
+        assert!(verify_narinfo_signature(fingerprint, &sigs, &trusted_keys).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_signature_over_a_different_fingerprint() {
+        let (signing_key, verifying_key) = test_keypair();
+        let signed_fingerprint = "1;/nix/store/abc-foo-1.0;sha256:deadbeef;123;";
+        let sigs = sign(&signing_key, signed_fingerprint);
+
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(String::from("test-1"), verifying_key);
+
+        // as if a tampered References field changed the reconstructed fingerprint
+        let tampered_fingerprint =
+            "1;/nix/store/abc-foo-1.0;sha256:deadbeef;123;/nix/store/evil-1.0";
+        assert!(verify_narinfo_signature(tampered_fingerprint, &sigs, &trusted_keys).is_err());
+    }
+
+    #[test]
+    fn rejects_when_no_sig_names_a_trusted_key() {
+        let (_signing_key, verifying_key) = test_keypair();
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(String::from("test-1"), verifying_key);
+
+        let sigs = vec![(String::from("someone-else-1"), String::from("aGVsbG8="))];
+        assert!(verify_narinfo_signature("1;...", &sigs, &trusted_keys).is_err());
+    }
+
+    #[test]
+    fn accepts_unconditionally_when_no_keys_are_trusted() {
+        let trusted_keys = TrustedKeys::new();
+        assert!(verify_narinfo_signature("1;...", &[], &trusted_keys).is_ok());
+    }
+}